@@ -1,14 +1,19 @@
 //! Iterator over database spaces
 
+use std::iter::FusedIterator;
+
 use error::{ErrorKind, Result};
 use field::{self, field_size, Header};
 use field::iterator::FieldIterator;
 
 macro_rules! try_next {
-	($t: expr) => {
+	($self: expr, $t: expr) => {
 		match $t {
 			Ok(ok) => ok,
-			Err(err) => return Some(Err(err.into())),
+			Err(err) => {
+				$self.done = true;
+				return Some(Err(err.into()));
+			},
 		}
 	}
 }
@@ -37,7 +42,12 @@ pub struct SpaceIterator<'a> {
 	data: &'a [u8],
 	field_body_size: usize,
 	offset: usize,
+	back_offset: usize,
 	peeked: Option<Result<Space<'a>>>,
+	/// Set once `next()`/`next_back()` has produced `None` or an `Err`, so
+	/// further calls keep yielding `None` instead of re-entering a scan from a
+	/// possibly corrupt offset.
+	done: bool,
 }
 
 impl<'a> SpaceIterator<'a> {
@@ -46,7 +56,9 @@ impl<'a> SpaceIterator<'a> {
 			data,
 			field_body_size,
 			offset,
+			back_offset: data.len(),
 			peeked: None,
+			done: false,
 		}
 	}
 
@@ -66,6 +78,87 @@ impl<'a> SpaceIterator<'a> {
 
 		self.peeked.as_ref()
 	}
+
+	/// Wrap this iterator so that runs of consecutive `Empty` spaces are
+	/// merged into a single `EmptySpace` spanning the whole run, instead of
+	/// one per underlying field.
+	pub fn coalesced(self) -> CoalescedSpaceIterator<'a> {
+		CoalescedSpaceIterator { inner: self }
+	}
+
+	/// Like `next()`, but merges a run of consecutive `Empty` spaces into one.
+	fn next_coalesced(&mut self) -> Option<Result<Space<'a>>> {
+		let mut empty = match self.next()? {
+			Ok(Space::Empty(empty)) => empty,
+			other => return Some(other),
+		};
+
+		while let Some(Ok(Space::Empty(next_empty))) = self.peek() {
+			empty.len += next_empty.len;
+			self.next();
+		}
+
+		Some(Ok(Space::Empty(empty)))
+	}
+
+	/// Scan forward from the current position and return the first empty
+	/// region of at least `min_len` bytes, coalescing adjacent empty fields
+	/// first so fragmentation across field boundaries doesn't hide a usable
+	/// hole. Rewinds to the start of the returned region before returning, so
+	/// a caller that only uses part of it can reclaim the unused tail with
+	/// `move_offset_forward(empty.offset + bytes_used)` on this same
+	/// iterator.
+	pub fn find_first_fit(&mut self, min_len: usize) -> Result<Option<EmptySpace>> {
+		loop {
+			match self.next_coalesced() {
+				Some(Ok(Space::Empty(empty))) => if empty.len >= min_len {
+					self.offset = empty.offset;
+					self.peeked = None;
+					self.done = false;
+					return Ok(Some(empty));
+				},
+				Some(Ok(Space::Occupied(_))) => {},
+				Some(Err(err)) => return Err(err),
+				None => return Ok(None),
+			}
+		}
+	}
+
+	/// Scan the whole remaining slice and return the smallest coalesced empty
+	/// region of at least `min_len` bytes, ties broken by the lowest offset.
+	/// Unlike `find_first_fit`, this consumes the entire remaining slice from
+	/// the iterator regardless of which region is returned; if the caller
+	/// only uses part of the returned region, resume scanning with a fresh
+	/// `SpaceIterator` at the real resume offset rather than reusing this
+	/// one.
+	pub fn find_best_fit(&mut self, min_len: usize) -> Result<Option<EmptySpace>> {
+		let mut best: Option<EmptySpace> = None;
+		loop {
+			match self.next_coalesced() {
+				Some(Ok(Space::Empty(empty))) => if empty.len >= min_len
+					&& best.as_ref().map_or(true, |best| empty.len < best.len)
+				{
+					best = Some(empty);
+				},
+				Some(Ok(Space::Occupied(_))) => {},
+				Some(Err(err)) => return Err(err),
+				None => return Ok(best),
+			}
+		}
+	}
+}
+
+/// Iterator adapter returned by `SpaceIterator::coalesced()`.
+pub struct CoalescedSpaceIterator<'a> {
+	inner: SpaceIterator<'a>,
+}
+
+impl<'a> Iterator for CoalescedSpaceIterator<'a> {
+	type Item = Result<Space<'a>>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		self.inner.next_coalesced()
+	}
 }
 
 impl<'a> Iterator for SpaceIterator<'a> {
@@ -76,16 +169,21 @@ impl<'a> Iterator for SpaceIterator<'a> {
 			return Some(peeked)
 		}
 
-		if self.data[self.offset..].is_empty() {
+		if self.done {
+			return None;
+		}
+
+		if self.offset >= self.back_offset {
+			self.done = true;
 			return None;
 		}
 
 		let mut prev_header = None;
 		let mut start = self.offset;
 		let field_size = field_size(self.field_body_size);
-		let mut inner = try_next!(FieldIterator::new(&self.data[self.offset..], self.field_body_size));
+		let mut inner = try_next!(self, FieldIterator::new(&self.data[self.offset..self.back_offset], self.field_body_size));
 		while let Some(field) = inner.next() {
-			let header = try_next!(field.header());
+			let header = try_next!(self, field.header());
 			match header {
 				Header::Continued => match prev_header {
 					// ommit continued fields at the beginning
@@ -98,6 +196,7 @@ impl<'a> Iterator for SpaceIterator<'a> {
 						self.offset += field_size;
 					},
 					Some(Header::Deleted) | Some(Header::Uninitialized) => {
+						self.done = true;
 						return Some(Err(ErrorKind::Field(field::ErrorKind::InvalidHeader).into()))
 					},
 				},
@@ -144,6 +243,127 @@ impl<'a> Iterator for SpaceIterator<'a> {
 			})),
 		})
 	}
+
+	fn size_hint(&self) -> (usize, Option<usize>) {
+		// A peeked item was already scanned past `self.offset`, but `next()`
+		// still returns it.
+		let peeked = if self.peeked.is_some() { 1 } else { 0 };
+		if self.done || self.offset >= self.back_offset {
+			return (peeked, Some(peeked));
+		}
+
+		// Each space is at least one field wide, so the remaining field count
+		// is an upper bound on the remaining number of spaces.
+		let upper = (self.back_offset - self.offset) / field_size(self.field_body_size);
+		(peeked, Some(upper + peeked))
+	}
+}
+
+impl<'a> FusedIterator for SpaceIterator<'a> {}
+
+impl<'a> DoubleEndedIterator for SpaceIterator<'a> {
+	fn next_back(&mut self) -> Option<Self::Item> {
+		if self.done {
+			return None;
+		}
+
+		if self.offset >= self.back_offset {
+			self.done = true;
+			return None;
+		}
+
+		let field_size = field_size(self.field_body_size);
+		let end = self.back_offset;
+		// Header of the last field absorbed so far, i.e. the field immediately to
+		// the right of the one we're about to read. `None` means we haven't
+		// classified anything yet this call.
+		let mut tail_header = None;
+		// Offset of the most recently absorbed Inserted field: the current
+		// best candidate for where the occupied run actually starts. A run of
+		// bare Continued fields merged in from further back might, once we
+		// reach the front, turn out to have nothing real before them -- the
+		// forward scan drops such a leading run silently without ever
+		// assigning it a `start` -- so `self.back_offset` alone isn't enough
+		// to know where the emitted region should begin.
+		let mut anchor_offset = None;
+
+		while self.offset < self.back_offset {
+			let field_start = self.back_offset - field_size;
+			let mut inner = try_next!(self, FieldIterator::new(&self.data[field_start..self.back_offset], self.field_body_size));
+			let field = inner.next().expect("slice is exactly one field wide");
+			let header = try_next!(self, field.header());
+
+			match (tail_header, header) {
+				// Nothing classified yet this call: absorb unconditionally.
+				// We can't tell a split from a merge until we've looked at
+				// the field behind this one.
+				(None, _) => {
+					self.back_offset = field_start;
+				},
+				// Two raw Inserted headers with nothing between them is the
+				// only thing that splits an occupied run in the forward
+				// scan; an Inserted directly preceded by a real empty field
+				// ends the run the same way. Either way, stop without
+				// consuming this field and let the next call pick it up.
+				(Some(Header::Inserted), Header::Inserted)
+				| (Some(Header::Inserted), Header::Deleted)
+				| (Some(Header::Inserted), Header::Uninitialized) => {
+					let offset = anchor_offset.expect("tail_header == Some(Inserted) implies an anchor was recorded");
+					return Some(Ok(Space::Occupied(OccupiedSpace {
+						offset,
+						data: &self.data[offset..end],
+					})));
+				},
+				// Continued always merges into whatever occupied run
+				// precedes it, and an Inserted directly preceded by a
+				// Continued merges into the same run too -- it only splits
+				// when preceded by another raw Inserted (handled above).
+				(Some(Header::Inserted), Header::Continued)
+				| (Some(Header::Continued), Header::Continued)
+				| (Some(Header::Continued), Header::Inserted) => {
+					self.back_offset = field_start;
+				},
+				// An occupied run can't be preceded by a real empty field
+				// without an Inserted anchoring it first -- the same invalid
+				// sequence the forward scan rejects.
+				(Some(Header::Continued), Header::Deleted) | (Some(Header::Continued), Header::Uninitialized) => {
+					self.done = true;
+					return Some(Err(ErrorKind::Field(field::ErrorKind::InvalidHeader).into()))
+				},
+				// An empty space is always a single field (mirrors the forward
+				// scan, which never merges consecutive empty fields): whatever
+				// comes before it -- Inserted, Continued or another empty field
+				// -- belongs to a different space, so stop without consuming it
+				// and let the next call pick it up.
+				(Some(Header::Deleted), _) | (Some(Header::Uninitialized), _) => {
+					return Some(Ok(Space::Empty(EmptySpace {
+						offset: self.back_offset,
+						len: end - self.back_offset,
+					})));
+				},
+			}
+
+			if header == Header::Inserted {
+				anchor_offset = Some(field_start);
+			}
+			tail_header = Some(header);
+		}
+
+		match tail_header {
+			None => None,
+			// An all-Continued run that never found an anchoring Inserted is
+			// the "continued at the beginning" case -- forward iteration
+			// drops it silently, so we don't emit it either.
+			Some(Header::Inserted) | Some(Header::Continued) => anchor_offset.map(|offset| Ok(Space::Occupied(OccupiedSpace {
+				offset,
+				data: &self.data[offset..end],
+			}))),
+			Some(Header::Deleted) | Some(Header::Uninitialized) => Some(Ok(Space::Empty(EmptySpace {
+				offset: self.back_offset,
+				len: end - self.back_offset,
+			}))),
+		}
+	}
 }
 
 #[cfg(test)]
@@ -289,4 +509,256 @@ mod tests {
 		let mut iterator = SpaceIterator::new(data, field_body_size, offset);
 		assert!(iterator.next().unwrap().is_err());
 	}
+
+	#[test]
+	fn test_space_iterator_coalesced_one_long_space2() {
+		let data = &[0, 0, 0, 0, 0, 0, 0, 0];
+		let field_body_size = 3;
+		let offset = 0;
+
+		let first_elem = Space::Empty(EmptySpace { offset, len: 8 });
+		let mut iterator = SpaceIterator::new(data, field_body_size, offset).coalesced();
+		assert_eq!(first_elem, iterator.next().unwrap().unwrap());
+		assert!(iterator.next().is_none());
+	}
+
+	#[test]
+	fn test_space_iterator_coalesced_two_different_spaces1() {
+		let data = &[1, 1, 1, 1, 0, 0, 0, 0];
+		let field_body_size = 3;
+		let offset = 0;
+
+		let first_elem = Space::Occupied(OccupiedSpace { offset, data: &data[0..4] });
+		let second_elem = Space::Empty(EmptySpace { offset: offset + 4, len: 4 });
+		let mut iterator = SpaceIterator::new(data, field_body_size, offset).coalesced();
+		assert_eq!(first_elem, iterator.next().unwrap().unwrap());
+		assert_eq!(second_elem, iterator.next().unwrap().unwrap());
+		assert!(iterator.next().is_none());
+	}
+
+	#[test]
+	fn test_space_iterator_coalesced_start_from_continued2() {
+		let data = &[
+			2, 0, 0, 0,
+			2, 0, 0, 0,
+			0, 0, 0, 0
+		];
+		let field_body_size = 3;
+		let offset = 0;
+
+		let first_elem = Space::Empty(EmptySpace { offset: 8, len: 4 });
+		let mut iterator = SpaceIterator::new(data, field_body_size, offset).coalesced();
+		assert_eq!(first_elem, iterator.next().unwrap().unwrap());
+		assert!(iterator.next().is_none());
+	}
+
+	#[test]
+	fn test_space_iterator_find_first_fit() {
+		let data = &[0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0];
+		let field_body_size = 3;
+		let offset = 0;
+
+		let mut iterator = SpaceIterator::new(data, field_body_size, offset);
+		let fit = iterator.find_first_fit(4).unwrap().unwrap();
+		assert_eq!(fit, EmptySpace { offset: 0, len: 4 });
+	}
+
+	#[test]
+	fn test_space_iterator_find_first_fit_skips_too_small() {
+		let data = &[0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+		let field_body_size = 3;
+		let offset = 0;
+
+		let mut iterator = SpaceIterator::new(data, field_body_size, offset);
+		let fit = iterator.find_first_fit(8).unwrap().unwrap();
+		assert_eq!(fit, EmptySpace { offset: 8, len: 8 });
+	}
+
+	#[test]
+	fn test_space_iterator_find_first_fit_resumes_after_partial_use() {
+		// find_first_fit rewinds to the start of the returned region, so a
+		// caller that only uses part of it can reclaim the unused tail via
+		// move_offset_forward on the same iterator.
+		let data = &[0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0];
+		let field_body_size = 3;
+		let offset = 0;
+
+		let mut iterator = SpaceIterator::new(data, field_body_size, offset);
+		let fit = iterator.find_first_fit(4).unwrap().unwrap();
+		assert_eq!(fit, EmptySpace { offset: 0, len: 8 });
+
+		iterator.move_offset_forward(fit.offset + 4);
+		let remaining = iterator.find_first_fit(4).unwrap().unwrap();
+		assert_eq!(remaining, EmptySpace { offset: 4, len: 4 });
+	}
+
+	#[test]
+	fn test_space_iterator_find_first_fit_none() {
+		let data = &[1, 1, 1, 1];
+		let field_body_size = 3;
+		let offset = 0;
+
+		let mut iterator = SpaceIterator::new(data, field_body_size, offset);
+		assert!(iterator.find_first_fit(1).unwrap().is_none());
+	}
+
+	#[test]
+	fn test_space_iterator_find_best_fit() {
+		let data = &[0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0];
+		let field_body_size = 3;
+		let offset = 0;
+
+		let mut iterator = SpaceIterator::new(data, field_body_size, offset);
+		let fit = iterator.find_best_fit(4).unwrap().unwrap();
+		assert_eq!(fit, EmptySpace { offset: 12, len: 4 });
+	}
+
+	#[test]
+	fn test_space_iterator_find_best_fit_ties_broken_by_offset() {
+		let data = &[0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0];
+		let field_body_size = 3;
+		let offset = 0;
+
+		let mut iterator = SpaceIterator::new(data, field_body_size, offset);
+		let fit = iterator.find_best_fit(4).unwrap().unwrap();
+		assert_eq!(fit, EmptySpace { offset: 0, len: 4 });
+	}
+
+	#[test]
+	fn test_space_iterator_find_fit_propagates_error() {
+		let data = &[0, 0, 0, 0, 2, 0, 0, 0];
+		let field_body_size = 3;
+		let offset = 0;
+
+		let mut iterator = SpaceIterator::new(data, field_body_size, offset);
+		assert!(iterator.find_first_fit(1).is_err());
+	}
+
+	#[test]
+	fn test_space_iterator_fused_after_error() {
+		let data = &[0, 0, 0, 0, 2, 0, 0, 0];
+		let field_body_size = 3;
+		let offset = 0;
+
+		let mut iterator = SpaceIterator::new(data, field_body_size, offset);
+		assert!(iterator.next().unwrap().is_err());
+		assert!(iterator.next().is_none());
+		assert!(iterator.next().is_none());
+	}
+
+	#[test]
+	fn test_space_iterator_fused_after_exhaustion() {
+		let data = &[1, 1, 1, 1];
+		let field_body_size = 3;
+		let offset = 0;
+
+		let mut iterator = SpaceIterator::new(data, field_body_size, offset);
+		assert!(iterator.next().unwrap().is_ok());
+		assert!(iterator.next().is_none());
+		assert!(iterator.next().is_none());
+	}
+
+	#[test]
+	fn test_space_iterator_size_hint() {
+		let data = &[1, 1, 1, 1, 0, 0, 0, 0];
+		let field_body_size = 3;
+		let offset = 0;
+
+		let mut iterator = SpaceIterator::new(data, field_body_size, offset);
+		assert_eq!(iterator.size_hint(), (0, Some(2)));
+		iterator.next();
+		assert_eq!(iterator.size_hint(), (0, Some(1)));
+		iterator.next();
+		assert_eq!(iterator.size_hint(), (0, Some(0)));
+	}
+
+	fn rev_matches_forward(data: &[u8], field_body_size: usize, offset: usize) {
+		let forward: Vec<_> = SpaceIterator::new(data, field_body_size, offset)
+			.map(|result| result.unwrap())
+			.collect();
+		let mut backward: Vec<_> = SpaceIterator::new(data, field_body_size, offset)
+			.rev()
+			.map(|result| result.unwrap())
+			.collect();
+		backward.reverse();
+		assert_eq!(forward, backward);
+	}
+
+	#[test]
+	fn test_space_iterator_rev_empty() {
+		rev_matches_forward(&[], 3, 0);
+	}
+
+	#[test]
+	fn test_space_iterator_rev_one_uninitialized_element() {
+		rev_matches_forward(&[0, 1, 1, 1], 3, 0);
+	}
+
+	#[test]
+	fn test_space_iterator_rev_one_initialized_element() {
+		rev_matches_forward(&[1, 1, 1, 1], 3, 0);
+	}
+
+	#[test]
+	fn test_space_iterator_rev_two_different_spaces1() {
+		rev_matches_forward(&[1, 1, 1, 1, 0, 0, 0, 0], 3, 0);
+	}
+
+	#[test]
+	fn test_space_iterator_rev_two_different_spaces2() {
+		rev_matches_forward(&[0, 0, 0, 0, 1, 0, 0, 0], 3, 0);
+	}
+
+	#[test]
+	fn test_space_iterator_rev_two_inserts() {
+		rev_matches_forward(&[1, 0, 0, 0, 1, 2, 2, 2], 3, 0);
+	}
+
+	#[test]
+	fn test_space_iterator_rev_one_long_space1() {
+		rev_matches_forward(&[1, 0, 0, 0, 2, 0, 0, 0], 3, 0);
+	}
+
+	#[test]
+	fn test_space_iterator_rev_one_long_space2() {
+		rev_matches_forward(&[0, 0, 0, 0, 0, 0, 0, 0], 3, 0);
+	}
+
+	#[test]
+	fn test_space_iterator_rev_merged_run_with_interior_insert() {
+		// An Inserted field preceded by a Continued field merges into the
+		// same occupied run instead of starting a new one, even when that
+		// Inserted sits in the middle of the run (regression test: next_back
+		// used to split this into two Occupied spaces instead of one).
+		rev_matches_forward(&[
+			1, 0, 0, 0,
+			2, 0, 0, 0,
+			1, 0, 0, 0,
+			0, 0, 0, 0
+		], 3, 0);
+	}
+
+	#[test]
+	fn test_space_iterator_rev_start_from_continued1() {
+		rev_matches_forward(&[2, 0, 0, 0, 0, 0, 0, 0], 3, 0);
+	}
+
+	#[test]
+	fn test_space_iterator_rev_start_from_continued2() {
+		rev_matches_forward(&[
+			2, 0, 0, 0,
+			2, 0, 0, 0,
+			0, 0, 0, 0
+		], 3, 0);
+	}
+
+	#[test]
+	fn test_space_iterator_rev_continued_error() {
+		let data = &[0, 0, 0, 0, 2, 0, 0, 0];
+		let field_body_size = 3;
+		let offset = 0;
+
+		let mut iterator = SpaceIterator::new(data, field_body_size, offset);
+		assert!(iterator.next_back().unwrap().is_err());
+	}
 }
\ No newline at end of file